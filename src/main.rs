@@ -1,4 +1,4 @@
-use std::{error::Error, fmt::Display, fs::File, os::unix::fs::FileExt};
+use std::{error::Error, fmt::Display, fs::File};
 
 use temp_value::TempValue;
 
@@ -29,30 +29,56 @@ impl<'a> TryFrom<&'a [u8]> for DataLine<'a> {
 
         let bytes_len = bytes.len();
 
-        // The maximum length of the temp_value is 5 bytes (-99.9 has 5 bytes), so checking the
-        // last 6 bytes will be enough to determine the position of the `;`
-        // Also, the minimun length for the temp is 3 bytes (0.0 has 3 bytes)
-        let (idx, temp) = match &bytes[bytes_len - 6..bytes_len] {
-            b @ [_, _, b';', _, _, _] => (
-                bytes_len - 4,
-                TempValue::try_from(&b[3..]).map_err(|_| INVALID_LINE)?,
-            ),
-            b @ [_, b';', _, _, _, _] => (
-                bytes_len - 5,
-                TempValue::try_from(&b[2..]).map_err(|_| INVALID_LINE)?,
-            ),
-            b @ [b';', _, _, _, _, _] => (
-                bytes_len - 6,
-                TempValue::try_from(&b[1..]).map_err(|_| INVALID_LINE)?,
-            ),
-            _ => {
-                return Err(INVALID_LINE);
+        // Locate the `;` with a word-at-a-time (SWAR) scan instead of peeking at the last 6 bytes.
+        // This handles any city-name length and, since we're already reading every byte, folds the
+        // city name into the FNV accumulator in the same pass.
+        const SEMI: u64 = 0x3B3B_3B3B_3B3B_3B3B;
+        const LO: u64 = 0x0101_0101_0101_0101;
+        const HI: u64 = 0x8080_8080_8080_8080;
+
+        let mut idx = None;
+        let mut offset = 0;
+
+        while offset < bytes_len {
+            let remaining = bytes_len - offset;
+
+            // Load 8 bytes as a little-endian word. A final partial word is zero-padded; padding
+            // bytes can't match `;` (0x00 ^ 0x3B != 0), so a stray match past the line end is
+            // impossible.
+            let word = if remaining >= 8 {
+                u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+            } else {
+                let mut buf = [0u8; 8];
+                buf[..remaining].copy_from_slice(&bytes[offset..]);
+                u64::from_le_bytes(buf)
+            };
+
+            // Classic SWAR "byte equal to N" test: a zero byte in `x` marks a `;`.
+            let x = word ^ SEMI;
+            let mask = x.wrapping_sub(LO) & !x & HI;
+
+            if mask != 0 {
+                // Index of the first matching byte within the word.
+                let pos = (mask.trailing_zeros() / 8) as usize;
+                // Fold the city-name bytes that precede the `;` within this word.
+                bytes[offset..offset + pos]
+                    .iter()
+                    .for_each(|b| fnv::fnv_hash_byte(*b, &mut key));
+                idx = Some(offset + pos);
+                break;
             }
-        };
 
-        bytes[..idx]
-            .iter()
-            .for_each(|b| fnv::fnv_hash_byte(*b, &mut key));
+            // No `;` in this word: every (real) byte is part of the city name.
+            let take = remaining.min(8);
+            bytes[offset..offset + take]
+                .iter()
+                .for_each(|b| fnv::fnv_hash_byte(*b, &mut key));
+
+            offset += 8;
+        }
+
+        let idx = idx.ok_or(INVALID_LINE)?;
+        let temp = TempValue::try_from(&bytes[idx + 1..]).map_err(|_| INVALID_LINE)?;
 
         // Hash the length of the city name for a better chance of a unique hash
         fnv::fnv_hash_byte(idx as u8, &mut key);
@@ -78,8 +104,11 @@ struct CityData<'name> {
     min: TempValue,
     /// The maximum temperature recorded
     max: TempValue,
-    /// The average temperature recorded
-    acc: TempValue,
+    /// The running sum of every measurement, in fixed-point tenths of a degree.
+    /// This is a plain `i64` rather than a `TempValue`: summing up to a billion values (each up to
+    /// 999) overflows `i32` by orders of magnitude, so the accumulator has to be wider than the
+    /// per-line value type.
+    acc: i64,
     /// The count of measurements
     count: usize,
 }
@@ -97,13 +126,14 @@ impl<'name> CityData<'name> {
         self.min = self.min.min(value);
         self.max = self.max.max(value);
 
-        self.acc += value;
+        self.acc += value.tenths();
         self.count += 1;
     }
 
     /// Calculate the average temperature
-    fn avg(&self) -> f32 {
-        Into::<f32>::into(self.acc) / self.count as f32
+    fn avg(&self) -> f64 {
+        // `acc` holds tenths of a degree, so divide back to degrees before averaging.
+        self.acc as f64 / 10.0 / self.count as f64
     }
 
     /// Merge the data from another `CityData` into this one.
@@ -131,38 +161,79 @@ impl Display for CityData<'_> {
     }
 }
 
-/// A map to store the data for each city
-/// The map is implemented using a `lib::U64KeyHashMap` to store the data for each city.
+/// Capacity of the cities table. A power of two `>= MAX_CITIES`, generously over-provisioned so the
+/// load factor stays low and linear-probe runs stay short.
+const TABLE_CAP: usize = (MAX_CITIES * 4).next_power_of_two();
+
+/// A single slot of the open-addressing table.
+#[derive(Clone)]
+struct Slot<'a> {
+    key: u64,
+    data: CityData<'a>,
+}
+
+/// A map to store the data for each city.
+/// It's a flat, power-of-two-sized open-addressing table with linear probing. Because the FNV keys
+/// are already well-distributed, probe runs stay short and the inline slot storage avoids the
+/// bucket indirection and `Entry` machinery of the std `HashMap` on every one of a billion `add`s.
 struct CitiesMap<'a> {
-    data: fnv::U64KeyHashMap<CityData<'a>>,
+    slots: Vec<Option<Slot<'a>>>,
 }
 
 impl<'a> CitiesMap<'a> {
     fn new() -> Self {
-        CitiesMap {
-            // Create the map with enough capacity to avoid resizing
-            data: fnv::U64KeyHashMap::with_capacity_and_hasher(MAX_CITIES, Default::default()),
+        let mut slots = Vec::with_capacity(TABLE_CAP);
+        slots.resize(TABLE_CAP, None);
+        CitiesMap { slots }
+    }
+
+    /// Probe from `key & (cap - 1)` until we hit the matching key or the first empty slot,
+    /// returning that slot's index.
+    fn probe(&self, key: u64) -> usize {
+        let mut idx = key as usize & (TABLE_CAP - 1);
+        loop {
+            match &self.slots[idx] {
+                Some(slot) if slot.key == key => return idx,
+                None => return idx,
+                _ => idx = (idx + 1) & (TABLE_CAP - 1),
+            }
         }
     }
 
     /// Add a new line of data to the map
     fn add(&mut self, line: DataLine<'a>) {
-        self.data
-            .entry(line.key)
-            .or_insert_with(|| CityData::new(line.city))
-            .add(line.temperature)
+        let idx = self.probe(line.key);
+        match &mut self.slots[idx] {
+            Some(slot) => slot.data.add(line.temperature),
+            slot @ None => {
+                let mut data = CityData::new(line.city);
+                data.add(line.temperature);
+                *slot = Some(Slot {
+                    key: line.key,
+                    data,
+                });
+            }
+        }
     }
 
     /// Get an iterator over the data
     fn iter(&'a self) -> impl Iterator<Item = &'a CityData<'a>> {
-        self.data.values()
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.as_ref().map(|slot| &slot.data))
     }
 
     /// Merge the data from another `CitiesMap` into this one.
     fn merge(&mut self, other: &Self) {
-        other.data.iter().for_each(|(key, other_data)| {
-            self.data.entry(*key).or_default().merge(other_data);
-        });
+        for slot in other.slots.iter().flatten() {
+            let idx = self.probe(slot.key);
+            match &mut self.slots[idx] {
+                Some(existing) => existing.data.merge(&slot.data),
+                empty @ None => {
+                    *empty = Some(slot.clone());
+                }
+            }
+        }
     }
 }
 
@@ -176,23 +247,14 @@ fn print_results<'a>(results: impl Iterator<Item = &'a CityData<'a>>) {
     println!("{}{}}}", CURSOR_LEFT, CURSOR_LEFT);
 }
 
-/// Given a slice of the bytes of the file (chunk), process the data and return a `CitiesMap` with
-/// the results.
-fn process_chunk(buffer: &[u8]) -> CitiesMap {
-    // Create the map that'll store the data. This will ensure that the map has enough capacity to
-    // avoid resizing.
-    let mut map = CitiesMap::new();
-
+/// Given a slice of the bytes of the file (chunk), parse every line into the provided `CitiesMap`.
+/// The map is owned by the worker thread and reused across every chunk it claims.
+fn process_chunk<'a>(buffer: &'a [u8], map: &mut CitiesMap<'a>) {
     buffer
         .split(|&byte| byte == b'\n')
         .filter(|line| !line.is_empty())
         .filter_map(|line| DataLine::try_from(line).ok())
-        .fold(&mut map, |map, line| {
-            map.add(line);
-            map
-        });
-
-    map
+        .for_each(|line| map.add(line));
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -200,75 +262,191 @@ fn main() -> Result<(), Box<dyn Error>> {
     let file_path = std::env::args().nth(1).unwrap_or_else(|| FILE.to_string());
 
     let file = File::open(&file_path)?;
-    let file_size = file.metadata()?.len();
+    let file_size = file.metadata()?.len() as usize;
+
+    // Memory-map the whole file once so every thread can borrow from the same slice.
+    // This removes the per-thread `read_exact_at` + `Vec` allocation + `leak()` dance and makes
+    // the parser truly zero-copy: chunk boundaries are just index pairs into the mapping.
+    let mmap = mmap::Mmap::new(&file, file_size)?;
+    let data = mmap.as_slice();
 
     // Get the number of available threads
     let thread_count = std::thread::available_parallelism()?.get();
 
-    // To store all the threads handles
-    let mut threads = vec![];
+    // Carve the file into many small newline-aligned chunks that workers claim on demand. This
+    // decouples parallelism from the chunk count, so a thread that finishes early just grabs more
+    // work instead of leaving a core idle while the slowest thread drains.
+    let chunks = chunks::Chunks::new(data);
+
+    // Spawn as meany threads as available. Each one keeps a thread-local `CitiesMap` and folds in
+    // every chunk it claims; the maps are merged once all threads finish. Scoped threads let the
+    // workers borrow `data`/`chunks` directly, so there's no need to leak anything.
+    let map = std::thread::scope(|scope| {
+        let chunks = &chunks;
+        let mut threads = vec![];
+
+        for _ in 0..thread_count {
+            threads.push(scope.spawn(move || {
+                let mut map = CitiesMap::new();
+                while let Some(chunk) = chunks.next() {
+                    process_chunk(chunk, &mut map);
+                }
+                map
+            }));
+        }
 
-    // Calculate the size of the chunk of data each thread will process
-    let chunk_size = file_size / thread_count as u64;
+        // Wait for all the threads to finish and collect the results
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .reduce(|mut map, chunk_map| {
+                map.merge(&chunk_map);
+                map
+            })
+            .expect("Impossible to have no results.")
+    });
 
-    // Spawn as meany threads as available, each one processing a chunk of the file.
-    // Before spwaning the thread, we ensure that the chunk ends at a newline character to avoid
-    // having invalid lines.
-    let mut start = 0;
+    // Collect the results into a `Vec` and sort them by city name
+    let mut results = map.iter().collect::<Vec<_>>();
+    results.sort_by_key(|city_data| city_data.city);
 
-    for _ in 0..thread_count {
-        let mut end = start + chunk_size;
+    // Now itereate over the results and print them
+    print_results(results.into_iter());
 
-        if end < file_size {
-            let mut temp_buf = [0; 30];
-            let _ = file.read_exact_at(&mut temp_buf, end);
+    Ok(())
+}
 
-            let new_line_pos = temp_buf
-                .iter()
-                .position(|byte| byte == &b'\n')
-                .map(|pos| pos as u64)
-                .expect("Shouldn't happen");
+mod chunks {
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-            end += new_line_pos
-        } else {
-            end = file_size;
-        }
+    /// Target size for each work unit before it's snapped to the next newline. Small enough that
+    /// the slowest chunk can't stall the final merge, large enough that the atomic claim and the
+    /// per-chunk setup stay negligible.
+    const CHUNK_SIZE: usize = 4 * 1024 * 1024;
 
-        let file_path = file_path.clone();
-        threads.push(std::thread::spawn(move || {
-            let mut data = vec![0; (end - start) as usize];
+    /// Hands out newline-aligned slices of the mapped file to workers on demand.
+    /// A shared `AtomicUsize` cursor tracks the next unclaimed offset; workers repeatedly call
+    /// [`Chunks::next`] until it returns `None`.
+    pub struct Chunks<'a> {
+        data: &'a [u8],
+        cursor: AtomicUsize,
+    }
 
-            // TODO: Need to try using a BufReader to see if it's faster but I'm not sure
-            // if it's worth it
-            let infile = File::open(file_path).unwrap();
-            let _ = infile.read_exact_at(&mut data, start);
+    impl<'a> Chunks<'a> {
+        pub fn new(data: &'a [u8]) -> Self {
+            Chunks {
+                data,
+                cursor: AtomicUsize::new(0),
+            }
+        }
 
-            // TODO: Try scoped threads to avoid the need to leak the data
-            let data = data.leak();
-            process_chunk(data)
-        }));
+        /// Atomically claim the next chunk. The returned slice always ends on a newline boundary
+        /// (or at EOF), so lines are never split across chunks. Returns `None` once the whole file
+        /// has been handed out.
+        pub fn next(&self) -> Option<&'a [u8]> {
+            let len = self.data.len();
+
+            loop {
+                let start = self.cursor.load(Ordering::Relaxed);
+                if start >= len {
+                    return None;
+                }
+
+                // Snap the tentative end forward to (and past) the next newline.
+                let mut end = (start + CHUNK_SIZE).min(len);
+                if end < len {
+                    end += self.data[end..]
+                        .iter()
+                        .position(|&b| b == b'\n')
+                        .map(|pos| pos + 1)
+                        .unwrap_or(len - end);
+                }
+
+                // Only the worker that wins the CAS owns the chunk; losers retry from the new start.
+                if self
+                    .cursor
+                    .compare_exchange(start, end, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Some(&self.data[start..end]);
+                }
+            }
+        }
+    }
+}
 
-        start = end;
+mod mmap {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    // Just enough of the POSIX `mmap` surface for a read-only private mapping. The crate is already
+    // unix-only (it takes the raw fd via `AsRawFd`), so declaring the two syscalls we need keeps
+    // us dependency-free instead of pulling in `libc`.
+    const PROT_READ: i32 = 0x1;
+    const MAP_PRIVATE: i32 = 0x2;
+    const MAP_FAILED: isize = -1;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut std::ffi::c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut std::ffi::c_void;
+        fn munmap(addr: *mut std::ffi::c_void, len: usize) -> i32;
     }
 
-    // Wait for all the threads to finish and collect the results
-    let map = threads
-        .into_iter()
-        .map(|thread| thread.join().unwrap())
-        .reduce(|mut map, chunk_map| {
-            map.merge(&chunk_map);
-            map
-        })
-        .expect("Impossible to have no results.");
+    /// A read-only, private memory mapping of a whole file.
+    /// The mapping is unmapped when the `Mmap` is dropped, so the borrowed slice must not outlive it.
+    pub struct Mmap {
+        ptr: *mut std::ffi::c_void,
+        len: usize,
+    }
 
-    // Collect the results into a `Vec` and sort them by city name
-    let mut results = map.iter().collect::<Vec<_>>();
-    results.sort_by_key(|city_data| city_data.city);
+    // The mapping is read-only and never mutated after creation, so it's safe to share across threads.
+    unsafe impl Sync for Mmap {}
+    unsafe impl Send for Mmap {}
+
+    impl Mmap {
+        /// Map the first `len` bytes of `file` into memory.
+        pub fn new(file: &File, len: usize) -> std::io::Result<Self> {
+            // SAFETY: the file descriptor is valid for the duration of the call and we map with a
+            // null hint, letting the kernel pick the address.
+            let ptr = unsafe {
+                mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    PROT_READ,
+                    MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
 
-    // Now itereate over the results and print them
-    print_results(results.into_iter());
+            if ptr as isize == MAP_FAILED {
+                return Err(std::io::Error::last_os_error());
+            }
 
-    Ok(())
+            Ok(Mmap { ptr, len })
+        }
+
+        /// Borrow the mapped bytes as a slice.
+        pub fn as_slice(&self) -> &[u8] {
+            // SAFETY: `ptr` points at a valid `len`-byte read-only mapping until `self` is dropped.
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            // SAFETY: `ptr`/`len` describe the mapping created in `new`.
+            unsafe {
+                munmap(self.ptr, self.len);
+            }
+        }
+    }
 }
 
 mod fnv {
@@ -288,48 +466,19 @@ mod fnv {
         *hash ^= byte as u64;
         *hash = hash.wrapping_mul(FNV_PRIME);
     }
-
-    /// A `HashMap` that uses a `u64` as the key and a `TransparentHasher` as the hasher
-    pub type U64KeyHashMap<V> = std::collections::HashMap<u64, V, TransparentHasher>;
-
-    /// A transparent hasher that will hash a `u64` to itself
-    /// This will be used as the hasher for the `U64KeyHashMap`, meaning that the key
-    /// will be used as the hash itself.
-    #[derive(Default)]
-    pub(crate) struct TransparentHasher(u64);
-
-    // Make `TransparentHasher` behave as a `std::hash::Hasher`
-    impl std::hash::Hasher for TransparentHasher {
-        fn finish(&self) -> u64 {
-            self.0
-        }
-
-        fn write(&mut self, bytes: &[u8]) {
-            // We already have a `u64` so we can just convert the bytes to a `u64`
-            self.0 = u64::from_be_bytes(bytes.try_into().unwrap());
-        }
-
-        // We don't want to rely on the default implementation of `write_u64` because it's
-        // based on the implementation of `write` and we already have a `u64`
-        fn write_u64(&mut self, i: u64) {
-            self.0 = i;
-        }
-    }
-
-    // ... and as a `std::hash::BuildHasher`
-    impl std::hash::BuildHasher for TransparentHasher {
-        type Hasher = TransparentHasher;
-
-        fn build_hasher(&self) -> Self::Hasher {
-            Default::default()
-        }
-    }
 }
 
 mod temp_value {
     #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
     pub struct TempValue(i32);
 
+    impl TempValue {
+        /// The raw fixed-point value, in tenths of a degree, widened for accumulation.
+        pub fn tenths(self) -> i64 {
+            self.0 as i64
+        }
+    }
+
     /// Support the `+` operator
     impl std::ops::Add for TempValue {
         type Output = Self;